@@ -1,33 +1,47 @@
-// cargo run -- corpus.csv targets.json byteclasser_output.csv
+// cargo run -- corpus.csv targets.json byteclasser_output.csv [--overlapping] [--format csv|jsonl]
+//   [--text-field name] [--select cols] [--id-column name] [--confidence]
+//   [--normalize none|length|softmax] [--weight-uniqueness]
+// cargo run -- train corpus.csv label_column targets.json
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
 use std::error::Error;
 use std::fs::File;
-use std::path::Path;
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::sync::Arc;
 use rayon::prelude::*;
 use csv::{ReaderBuilder, WriterBuilder};
 
-#[derive(Debug, Deserialize)]
+/// Training thresholds used by the `train` subcommand; they are also
+/// written into the generated `targets.json` metadata.
+const DEFAULT_NGRAM_RANGE: (u32, u32) = (2, 4);
+const DEFAULT_MIN_FREQUENCY: u32 = 5;
+const DEFAULT_MIN_UNIQUENESS: f64 = 0.6;
+
+/// Rows are read and scored in chunks of this size so a multi-gigabyte
+/// corpus never needs to be fully resident in memory.
+const CHUNK_SIZE: usize = 10_000;
+
+#[derive(Debug, Deserialize, Serialize)]
 struct TargetJson {
     metadata: Metadata,
     targets: HashMap<String, LabelTarget>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct Metadata {
     min_frequency: u32,
     min_uniqueness: f64,
     ngram_range: (u32, u32),
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct LabelTarget {
     label: String,
     targets: Vec<NGramTarget>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 struct NGramTarget {
     text: String,
     weight: f64,
@@ -39,145 +53,886 @@ struct NGramTarget {
 #[derive(Debug, Serialize)]
 struct RowResult {
     row_id: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
     text: String,
     #[serde(flatten)]
     scores: HashMap<String, f64>,
+    predicted_label: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confidence: Option<f64>,
+}
+
+/// Whether pattern matches are counted every time they occur (even when they
+/// overlap a previous match of the same pattern) or only once a prior match
+/// of that same pattern has been fully consumed (greedy, left to right,
+/// tracked separately for each pattern).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MatchMode {
+    Overlapping,
+    NonOverlapping,
+}
+
+/// One label/weight contribution attached to a trie node, recording which
+/// pattern produced it so non-overlapping counting can track that pattern's
+/// own consumed range independently of every other pattern.
+#[derive(Debug, Clone)]
+struct Output {
+    label: String,
+    weight: f64,
+    uniqueness: f64,
+    length: usize,
+    pattern_id: usize,
+}
+
+#[derive(Debug, Default)]
+struct AcNode {
+    children: HashMap<u8, usize>,
+    fail: usize,
+    outputs: Vec<Output>,
+}
+
+/// Aho-Corasick automaton over every `bytes_pattern` across all labels,
+/// built once from the loaded targets and shared (via `Arc`) across Rayon
+/// workers so each row is scored with a single pass over its bytes instead
+/// of one pass per pattern.
+#[derive(Debug)]
+struct Automaton {
+    nodes: Vec<AcNode>,
+    pattern_count: usize,
+}
+
+impl Automaton {
+    fn build(targets: &HashMap<String, LabelTarget>) -> Automaton {
+        let mut nodes = vec![AcNode::default()];
+        let mut pattern_count = 0;
+
+        for (label, label_target) in targets {
+            for target in &label_target.targets {
+                let pattern = match hex::decode(&target.bytes_pattern) {
+                    Ok(pattern) if !pattern.is_empty() => pattern,
+                    _ => continue,
+                };
+
+                let mut current = 0;
+                for &byte in &pattern {
+                    current = match nodes[current].children.get(&byte) {
+                        Some(&next) => next,
+                        None => {
+                            nodes.push(AcNode::default());
+                            let next = nodes.len() - 1;
+                            nodes[current].children.insert(byte, next);
+                            next
+                        }
+                    };
+                }
+
+                let pattern_id = pattern_count;
+                pattern_count += 1;
+                nodes[current].outputs.push(Output {
+                    label: label.clone(),
+                    weight: target.weight,
+                    uniqueness: target.uniqueness,
+                    length: pattern.len(),
+                    pattern_id,
+                });
+            }
+        }
+
+        // BFS over the trie to compute failure links: the root's children
+        // fail to the root, and every other node's fail target is found by
+        // following its parent's fail link until a matching child turns up.
+        // Each node's outputs are merged with its fail target's outputs so
+        // a match that is only a suffix of the current path still reports.
+        let mut queue = VecDeque::new();
+        let root_children: Vec<usize> = nodes[0].children.values().copied().collect();
+        for child in root_children {
+            nodes[child].fail = 0;
+            queue.push_back(child);
+        }
+
+        while let Some(current) = queue.pop_front() {
+            let children: Vec<(u8, usize)> = nodes[current]
+                .children
+                .iter()
+                .map(|(&byte, &child)| (byte, child))
+                .collect();
+
+            for (byte, child) in children {
+                let mut fail = nodes[current].fail;
+                let fail_target = loop {
+                    if let Some(&next) = nodes[fail].children.get(&byte) {
+                        break next;
+                    } else if fail == 0 {
+                        break 0;
+                    } else {
+                        fail = nodes[fail].fail;
+                    }
+                };
+
+                nodes[child].fail = fail_target;
+                let mut inherited = nodes[fail_target].outputs.clone();
+                nodes[child].outputs.append(&mut inherited);
+                queue.push_back(child);
+            }
+        }
+
+        Automaton { nodes, pattern_count }
+    }
+
+    fn goto(&self, mut state: usize, byte: u8) -> usize {
+        loop {
+            if let Some(&next) = self.nodes[state].children.get(&byte) {
+                return next;
+            } else if state == 0 {
+                return 0;
+            } else {
+                state = self.nodes[state].fail;
+            }
+        }
+    }
+
+    /// Walk `text` once, following goto edges or failure links, adding each
+    /// matched pattern's weight (multiplied by its uniqueness when
+    /// `use_uniqueness` is set) to its label's score.
+    fn scan(&self, text: &[u8], mode: MatchMode, use_uniqueness: bool) -> HashMap<String, f64> {
+        let mut label_scores: HashMap<String, f64> = HashMap::new();
+        let mut next_pos = vec![0usize; self.pattern_count];
+        let mut state = 0usize;
+
+        for (i, &byte) in text.iter().enumerate() {
+            state = self.goto(state, byte);
+
+            for output in &self.nodes[state].outputs {
+                let end = i + 1;
+
+                let should_count = match mode {
+                    MatchMode::Overlapping => true,
+                    MatchMode::NonOverlapping => end - output.length >= next_pos[output.pattern_id],
+                };
+
+                if should_count {
+                    let contribution = if use_uniqueness {
+                        output.weight * output.uniqueness
+                    } else {
+                        output.weight
+                    };
+                    *label_scores.entry(output.label.clone()).or_insert(0.0) += contribution;
+                    if mode == MatchMode::NonOverlapping {
+                        next_pos[output.pattern_id] = end;
+                    }
+                }
+            }
+        }
+
+        label_scores
+    }
+}
+
+/// Bundles the deserialized targets with the automaton built from them so
+/// both can be shared across Rayon workers behind a single `Arc`.
+struct Shared {
+    targets: TargetJson,
+    automaton: Automaton,
+    match_mode: MatchMode,
+    labels: Vec<String>,
+    with_confidence: bool,
+    normalize_mode: NormalizeMode,
+    use_uniqueness: bool,
+}
+
+/// How raw `count * weight` label scores are rescaled before being reported,
+/// applied uniformly across every label after scoring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NormalizeMode {
+    /// Raw summed scores, unchanged.
+    None,
+    /// Each label's score divided by the row's byte length, so long rows
+    /// no longer dominate short ones.
+    Length,
+    /// Scores exponentiated and rescaled into a probability distribution
+    /// over labels that sums to 1.
+    Softmax,
+}
+
+/// Rescales `scores` in place according to `mode`.
+fn normalize_scores(scores: &mut HashMap<String, f64>, mode: NormalizeMode, text_len: usize) {
+    match mode {
+        NormalizeMode::None => {}
+        NormalizeMode::Length => {
+            let len = text_len.max(1) as f64;
+            for value in scores.values_mut() {
+                *value /= len;
+            }
+        }
+        NormalizeMode::Softmax => {
+            let max = scores.values().cloned().fold(f64::MIN, f64::max);
+            let exps: HashMap<String, f64> = scores.iter()
+                .map(|(label, &value)| (label.clone(), (value - max).exp()))
+                .collect();
+            let sum: f64 = exps.values().sum();
+            for (label, value) in scores.iter_mut() {
+                *value = exps[label] / sum;
+            }
+        }
+    }
+}
+
+/// Input/output record format. CSV is the original format; jsonl reads and
+/// writes one JSON object per line so the tool can slot into log-processing
+/// pipelines that already speak newline-delimited JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Csv,
+    Jsonl,
+}
+
+struct CliArgs {
+    input_path: String,
+    targets_path: String,
+    output_path: String,
+    match_mode: MatchMode,
+    format: Format,
+    text_field: Option<String>,
+    select: Option<String>,
+    id_column: Option<String>,
+    with_confidence: bool,
+    normalize_mode: NormalizeMode,
+    use_uniqueness: bool,
+}
+
+fn usage_and_exit(program: &str) -> ! {
+    eprintln!(
+        "Usage: {program} <input> <targets_json> <output> [--overlapping] [--format csv|jsonl] \
+         [--text-field name] [--select cols] [--id-column name] [--confidence] \
+         [--normalize none|length|softmax] [--weight-uniqueness]"
+    );
+    std::process::exit(1);
+}
+
+fn parse_args(args: &[String]) -> CliArgs {
+    if args.len() < 4 {
+        usage_and_exit(&args[0]);
+    }
+
+    let mut match_mode = MatchMode::NonOverlapping;
+    let mut format = Format::Csv;
+    let mut text_field = None;
+    let mut select = None;
+    let mut id_column = None;
+    let mut with_confidence = false;
+    let mut normalize_mode = NormalizeMode::None;
+    let mut use_uniqueness = false;
+
+    let mut i = 4;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--overlapping" => match_mode = MatchMode::Overlapping,
+            "--confidence" => with_confidence = true,
+            "--weight-uniqueness" => use_uniqueness = true,
+            "--format" => {
+                i += 1;
+                format = match args.get(i).map(String::as_str) {
+                    Some("csv") => Format::Csv,
+                    Some("jsonl") => Format::Jsonl,
+                    _ => usage_and_exit(&args[0]),
+                };
+            }
+            "--text-field" => {
+                i += 1;
+                match args.get(i) {
+                    Some(field) => text_field = Some(field.clone()),
+                    None => usage_and_exit(&args[0]),
+                }
+            }
+            "--select" => {
+                i += 1;
+                match args.get(i) {
+                    Some(spec) => select = Some(spec.clone()),
+                    None => usage_and_exit(&args[0]),
+                }
+            }
+            "--id-column" => {
+                i += 1;
+                match args.get(i) {
+                    Some(name) => id_column = Some(name.clone()),
+                    None => usage_and_exit(&args[0]),
+                }
+            }
+            "--normalize" => {
+                i += 1;
+                normalize_mode = match args.get(i).map(String::as_str) {
+                    Some("none") => NormalizeMode::None,
+                    Some("length") => NormalizeMode::Length,
+                    Some("softmax") => NormalizeMode::Softmax,
+                    _ => usage_and_exit(&args[0]),
+                };
+            }
+            _ => usage_and_exit(&args[0]),
+        }
+        i += 1;
+    }
+
+    CliArgs {
+        input_path: args[1].clone(),
+        targets_path: args[2].clone(),
+        output_path: args[3].clone(),
+        match_mode,
+        format,
+        text_field,
+        select,
+        id_column,
+        with_confidence,
+        normalize_mode,
+        use_uniqueness,
+    }
+}
+
+/// One column reference from a `--select`/`--id-column` spec: either a
+/// literal header name or a 1-based column index, resolved against the
+/// CSV header the same way xsv's select syntax does.
+fn resolve_column(token: &str, headers: &csv::StringRecord) -> Result<usize, Box<dyn Error>> {
+    if let Ok(n) = token.parse::<usize>() {
+        if n == 0 || n > headers.len() {
+            return Err(format!("column index {n} out of range").into());
+        }
+        return Ok(n - 1);
+    }
+    headers.iter()
+        .position(|h| h == token)
+        .ok_or_else(|| format!("column '{token}' not found in header").into())
+}
+
+/// Parses an xsv-style select spec (`2`, `name`, `2-4`, `a,c-e`) into
+/// 0-based column indices, in the order given.
+fn parse_select(spec: &str, headers: &csv::StringRecord) -> Result<Vec<usize>, Box<dyn Error>> {
+    let mut indices = Vec::new();
+    for part in spec.split(',') {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let start_idx = resolve_column(start, headers)?;
+                let end_idx = resolve_column(end, headers)?;
+                if start_idx <= end_idx {
+                    indices.extend(start_idx..=end_idx);
+                } else {
+                    indices.extend((end_idx..=start_idx).rev());
+                }
+            }
+            None => indices.push(resolve_column(part, headers)?),
+        }
+    }
+    Ok(indices)
+}
+
+/// A row reduced to just what scoring needs: the text to scan and, if an
+/// id column was configured, the value to carry through unchanged.
+struct RawRow {
+    id: Option<String>,
+    text: String,
+}
+
+/// Reads raw rows in fixed-size chunks, dropping CSV/JSONL-specific parsing
+/// once each row has been reduced to its `RawRow`.
+enum RowSource {
+    Csv {
+        reader: csv::Reader<File>,
+        selected: Option<Vec<usize>>,
+        id_index: Option<usize>,
+    },
+    Jsonl {
+        lines: std::io::Lines<BufReader<File>>,
+        selected: Option<Vec<String>>,
+        id_field: Option<String>,
+    },
+}
+
+impl RowSource {
+    fn open(
+        input_path: &str,
+        format: Format,
+        select: Option<&str>,
+        id_column: Option<&str>,
+    ) -> Result<RowSource, Box<dyn Error>> {
+        match format {
+            Format::Csv => {
+                let mut reader = ReaderBuilder::new().has_headers(true).from_path(input_path)?;
+                let headers = reader.headers()?.clone();
+                let selected = select.map(|spec| parse_select(spec, &headers)).transpose()?;
+                let id_index = id_column.map(|name| resolve_column(name, &headers)).transpose()?;
+                Ok(RowSource::Csv { reader, selected, id_index })
+            }
+            Format::Jsonl => {
+                // JSONL has no fixed header, so `--select`/`--id-column` are
+                // taken as literal JSON field names rather than the
+                // index/range spec `parse_select` resolves for CSV.
+                let selected = select.map(|spec| {
+                    spec.split(',').map(|field| field.trim().to_string()).collect()
+                });
+                Ok(RowSource::Jsonl {
+                    lines: BufReader::new(File::open(input_path)?).lines(),
+                    selected,
+                    id_field: id_column.map(str::to_string),
+                })
+            }
+        }
+    }
+
+    /// Returns the next chunk of up to `CHUNK_SIZE` rows, or `None` once the
+    /// underlying source has no more raw input. Rows that fail to parse are
+    /// skipped, so a returned chunk may be shorter than `CHUNK_SIZE` even
+    /// when more input remains.
+    fn next_chunk(&mut self, text_field: Option<&str>) -> Result<Option<Vec<RawRow>>, Box<dyn Error>> {
+        let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+        let mut saw_any = false;
+
+        match self {
+            RowSource::Csv { reader, selected, id_index } => {
+                for record in reader.records().take(CHUNK_SIZE) {
+                    saw_any = true;
+                    if let Ok(record) = record {
+                        let text = match selected {
+                            Some(indices) => indices.iter()
+                                .filter_map(|&idx| record.get(idx))
+                                .collect::<Vec<&str>>()
+                                .join(" "),
+                            None => record.iter().collect::<Vec<&str>>().join(" "),
+                        };
+                        let id = id_index.and_then(|idx| record.get(idx)).map(str::to_string);
+                        chunk.push(RawRow { id, text });
+                    }
+                }
+            }
+            RowSource::Jsonl { lines, selected, id_field } => {
+                for line in lines.by_ref().take(CHUNK_SIZE) {
+                    saw_any = true;
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let value: Value = match serde_json::from_str(&line) {
+                        Ok(value) => value,
+                        Err(_) => continue,
+                    };
+                    let id = id_field.as_deref()
+                        .and_then(|field| value.get(field))
+                        .and_then(Value::as_str)
+                        .map(str::to_string);
+                    let text = extract_text(&value, text_field, selected.as_deref(), id_field.as_deref());
+                    chunk.push(RawRow { id, text });
+                }
+            }
+        }
+
+        Ok(if saw_any { Some(chunk) } else { None })
+    }
+}
+
+/// Picks the text to score out of a JSONL record: `select` (named fields,
+/// joined in order) wins if given, then `text_field`, otherwise every
+/// string-valued field is concatenated (mirroring how CSV rows join every
+/// column) excluding whatever field `--id-column` pulled out separately, so
+/// the id doesn't leak into the scored text.
+fn extract_text(
+    value: &Value,
+    text_field: Option<&str>,
+    select: Option<&[String]>,
+    id_field: Option<&str>,
+) -> String {
+    if let Some(fields) = select {
+        return fields.iter()
+            .filter_map(|field| value.get(field).and_then(Value::as_str))
+            .collect::<Vec<&str>>()
+            .join(" ");
+    }
+
+    if let Some(field) = text_field {
+        return value.get(field)
+            .and_then(Value::as_str)
+            .unwrap_or("")
+            .to_string();
+    }
+
+    match value {
+        Value::Object(map) => map.iter()
+            .filter(|(key, _)| Some(key.as_str()) != id_field)
+            .filter_map(|(_, v)| v.as_str())
+            .collect::<Vec<&str>>()
+            .join(" "),
+        _ => String::new(),
+    }
+}
+
+/// Writes scored rows out as CSV (one column per label) or as NDJSON (one
+/// `RowResult` object per line).
+enum OutputSink {
+    Csv(Box<csv::Writer<File>>),
+    Jsonl(BufWriter<File>),
+}
+
+impl OutputSink {
+    fn open(
+        output_path: &str,
+        format: Format,
+        labels: &[String],
+        with_id: bool,
+        with_confidence: bool,
+    ) -> Result<OutputSink, Box<dyn Error>> {
+        match format {
+            Format::Csv => {
+                let mut writer = WriterBuilder::new().from_path(output_path)?;
+                let mut header = vec!["row_id".to_string()];
+                if with_id {
+                    header.push("id".to_string());
+                }
+                header.push("text".to_string());
+                header.extend(labels.iter().cloned());
+                header.push("predicted_label".to_string());
+                if with_confidence {
+                    header.push("confidence".to_string());
+                }
+                writer.write_record(&header)?;
+                Ok(OutputSink::Csv(Box::new(writer)))
+            }
+            Format::Jsonl => Ok(OutputSink::Jsonl(BufWriter::new(File::create(output_path)?))),
+        }
+    }
+
+    fn write_chunk(
+        &mut self,
+        labels: &[String],
+        with_id: bool,
+        with_confidence: bool,
+        results: &[RowResult],
+    ) -> Result<(), Box<dyn Error>> {
+        match self {
+            OutputSink::Csv(writer) => write_chunk_csv(writer, labels, with_id, with_confidence, results),
+            OutputSink::Jsonl(writer) => {
+                for result in results {
+                    serde_json::to_writer(&mut *writer, result)?;
+                    writer.write_all(b"\n")?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        match self {
+            OutputSink::Csv(writer) => Ok(writer.flush()?),
+            OutputSink::Jsonl(writer) => Ok(writer.flush()?),
+        }
+    }
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     // Parse command line arguments
     let args: Vec<String> = std::env::args().collect();
-    if args.len() != 4 {
-        eprintln!("Usage: {} <input_csv> <targets_json> <output_csv>", args[0]);
-        std::process::exit(1);
+
+    if args.get(1).map(String::as_str) == Some("train") {
+        return run_train(&args);
     }
 
+    let cli = parse_args(&args);
+
     // Load and parse the JSON targets file
     let targets: TargetJson = serde_json::from_reader(
-        File::open(&args[2])?
+        File::open(&cli.targets_path)?
     )?;
-    let targets = Arc::new(targets);
+    let automaton = Automaton::build(&targets.targets);
 
-    // Set up CSV reader
-    let mut reader = ReaderBuilder::new()
-        .has_headers(true)
-        .from_path(&args[1])?;
+    // The label set is known up front from the loaded targets, so the
+    // output header can be written before any row is read instead of
+    // waiting to see every result first.
+    let mut labels: Vec<String> = targets.targets.keys().cloned().collect();
+    labels.sort();
+
+    let shared = Arc::new(Shared {
+        targets,
+        automaton,
+        match_mode: cli.match_mode,
+        labels: labels.clone(),
+        with_confidence: cli.with_confidence,
+        normalize_mode: cli.normalize_mode,
+        use_uniqueness: cli.use_uniqueness,
+    });
 
-    // Read all records into memory
-    let records: Vec<csv::StringRecord> = reader.records()
-        .filter_map(Result::ok)
-        .collect();
+    let with_id = cli.id_column.is_some();
+    let mut source = RowSource::open(
+        &cli.input_path,
+        cli.format,
+        cli.select.as_deref(),
+        cli.id_column.as_deref(),
+    )?;
+    let mut sink = OutputSink::open(&cli.output_path, cli.format, &labels, with_id, cli.with_confidence)?;
 
-    // Process records in parallel
-    let results: Vec<RowResult> = records.par_iter()
-        .enumerate()
-        .map(|(idx, record)| {
-            process_row(idx, record, Arc::clone(&targets))
-        })
-        .collect();
+    // Read and score fixed-size chunks of rows, writing and dropping each
+    // chunk before the next is read, so memory use stays flat regardless
+    // of corpus size. Chunk summaries are folded together as we go.
+    let mut row_id = 0usize;
+    let mut summary = ChunkSummary::default();
 
-    // Write results to output CSV
-    write_results(&args[3], &results)?;
+    while let Some(chunk) = source.next_chunk(cli.text_field.as_deref())? {
+        let results: Vec<RowResult> = chunk.par_iter()
+            .enumerate()
+            .map(|(idx, row)| score_text(row_id + idx, row.id.clone(), row.text.clone(), Arc::clone(&shared)))
+            .collect();
+
+        summary = summary.merge(ChunkSummary::from_results(&results));
+        sink.write_chunk(&labels, with_id, cli.with_confidence, &results)?;
+        row_id += chunk.len();
+    }
+
+    sink.flush()?;
+    summary.print(&labels);
 
     Ok(())
 }
 
-fn process_row(
-    row_id: usize,
-    record: &csv::StringRecord,
-    targets: Arc<TargetJson>
-) -> RowResult {
-    let text = record.iter()
-        .collect::<Vec<&str>>()
-        .join(" ");
-    
-    let mut scores: HashMap<String, f64> = HashMap::new();
+/// Generates a `targets.json` from a labeled corpus: tallies per-label
+/// frequency counts for every byte n-gram in `DEFAULT_NGRAM_RANGE`, keeps
+/// the ones that are frequent and distinctive enough, and writes them out
+/// in the same shape `main` expects to read.
+fn run_train(args: &[String]) -> Result<(), Box<dyn Error>> {
+    if args.len() != 5 {
+        eprintln!(
+            "Usage: {} train <corpus_csv> <label_column> <targets_json>",
+            args[0]
+        );
+        std::process::exit(1);
+    }
+    let corpus_path = &args[2];
+    let label_column = &args[3];
+    let output_path = &args[4];
+
+    let mut reader = ReaderBuilder::new()
+        .has_headers(true)
+        .from_path(corpus_path)?;
 
-    // Calculate scores for each label
-    for (label, label_target) in &targets.targets {
-        let mut label_score = 0.0;
+    let headers = reader.headers()?.clone();
+    let label_idx = headers
+        .iter()
+        .position(|h| h == label_column)
+        .ok_or_else(|| format!("column '{label_column}' not found in corpus header"))?;
 
-        for target in &label_target.targets {
-            // Convert hex string to bytes
-            if let Ok(pattern) = hex::decode(&target.bytes_pattern) {
-                // Convert text to bytes for matching
-                let text_bytes = text.as_bytes();
-                
-                // Count occurrences of pattern in text
-                let count = find_occurrences(text_bytes, &pattern) as f64;
-                
-                // Add to label score
-                label_score += count * target.weight;
+    // n-gram bytes -> label -> occurrence count
+    let mut counts: HashMap<Vec<u8>, HashMap<String, u32>> = HashMap::new();
+
+    for record in reader.records() {
+        let record = record?;
+        let label = record.get(label_idx).unwrap_or("").to_string();
+        let text = record
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| *idx != label_idx)
+            .map(|(_, field)| field)
+            .collect::<Vec<&str>>()
+            .join(" ");
+        let bytes = text.as_bytes();
+
+        for n in DEFAULT_NGRAM_RANGE.0..=DEFAULT_NGRAM_RANGE.1 {
+            for ngram in extract_ngrams(bytes, n as usize) {
+                *counts.entry(ngram).or_default().entry(label.clone()).or_insert(0) += 1;
             }
         }
+    }
+
+    let mut targets: HashMap<String, LabelTarget> = HashMap::new();
+
+    for (ngram, label_counts) in counts {
+        let total_freq: u32 = label_counts.values().sum();
+        if total_freq < DEFAULT_MIN_FREQUENCY {
+            continue;
+        }
 
-        scores.insert(label.clone(), label_score);
+        let (dominant_label, dominant_count) = label_counts
+            .iter()
+            .max_by_key(|(_, &count)| count)
+            .map(|(label, &count)| (label.clone(), count))
+            .expect("label_counts is never empty");
+
+        let uniqueness = dominant_count as f64 / total_freq as f64;
+        if uniqueness < DEFAULT_MIN_UNIQUENESS {
+            continue;
+        }
+
+        let weight = uniqueness * (1.0 + (total_freq as f64).ln());
+
+        targets
+            .entry(dominant_label.clone())
+            .or_insert_with(|| LabelTarget {
+                label: dominant_label.clone(),
+                targets: Vec::new(),
+            })
+            .targets
+            .push(NGramTarget {
+                text: String::from_utf8_lossy(&ngram).into_owned(),
+                weight,
+                frequency: total_freq,
+                uniqueness,
+                bytes_pattern: hex::encode(&ngram),
+            });
     }
 
-    RowResult {
-        row_id,
-        text,
-        scores,
+    let target_json = TargetJson {
+        metadata: Metadata {
+            min_frequency: DEFAULT_MIN_FREQUENCY,
+            min_uniqueness: DEFAULT_MIN_UNIQUENESS,
+            ngram_range: DEFAULT_NGRAM_RANGE,
+        },
+        targets,
+    };
+
+    serde_json::to_writer_pretty(File::create(output_path)?, &target_json)?;
+
+    Ok(())
+}
+
+/// Every contiguous byte-slice of length `n` in `bytes`, in order.
+fn extract_ngrams(bytes: &[u8], n: usize) -> Vec<Vec<u8>> {
+    if n == 0 || bytes.len() < n {
+        return Vec::new();
     }
+    bytes.windows(n).map(|window| window.to_vec()).collect()
 }
 
-fn find_occurrences(text: &[u8], pattern: &[u8]) -> usize {
-    if pattern.is_empty() || text.len() < pattern.len() {
-        return 0;
+fn score_text(
+    row_id: usize,
+    id: Option<String>,
+    text: String,
+    shared: Arc<Shared>
+) -> RowResult {
+    let mut scores = shared.automaton.scan(text.as_bytes(), shared.match_mode, shared.use_uniqueness);
+
+    // Ensure every label gets a column even when it had no matches.
+    for label in shared.targets.targets.keys() {
+        scores.entry(label.clone()).or_insert(0.0);
     }
 
-    let mut count = 0;
-    let mut i = 0;
-    while i <= text.len() - pattern.len() {
-        if text[i..].starts_with(pattern) {
-            count += 1;
-            i += pattern.len();
-        } else {
-            i += 1;
-        }
+    normalize_scores(&mut scores, shared.normalize_mode, text.len());
+
+    // Argmax over scores, breaking ties in favor of the alphabetically
+    // first label by walking the sorted label list in order.
+    let (predicted_label, top_score) = shared.labels.iter()
+        .map(|label| (label.clone(), *scores.get(label).unwrap_or(&0.0)))
+        .fold((String::new(), f64::MIN), |best, candidate| {
+            if candidate.1 > best.1 { candidate } else { best }
+        });
+
+    let confidence = if shared.with_confidence {
+        let sum: f64 = scores.values().sum();
+        Some(if sum > 0.0 { top_score / sum } else { 0.0 })
+    } else {
+        None
+    };
+
+    RowResult {
+        row_id,
+        id,
+        text,
+        scores,
+        predicted_label,
+        confidence,
     }
-    count
 }
 
-fn write_results(
-    output_path: &str,
+/// Writes one chunk's rows using a pre-computed, already-written label
+/// header, so chunks can be streamed out independently of one another.
+fn write_chunk_csv(
+    writer: &mut csv::Writer<File>,
+    labels: &[String],
+    with_id: bool,
+    with_confidence: bool,
     results: &[RowResult]
 ) -> Result<(), Box<dyn Error>> {
-    let mut writer = WriterBuilder::new()
-        .from_path(output_path)?;
-
-    // Get all unique labels
-    let mut labels: Vec<String> = results.iter()
-        .flat_map(|r| r.scores.keys().cloned())
-        .collect::<std::collections::HashSet<_>>()
-        .into_iter()
-        .collect();
-    labels.sort();
-
-    // Write header
-    let mut header = vec!["row_id", "text"];
-    header.extend(labels.iter().map(|s| s.as_str()));
-    writer.write_record(&header)?;
-
-    // Write results
     for result in results {
-        let mut record = vec![
-            result.row_id.to_string(),
-            result.text.clone(),
-        ];
-        
+        let mut record = vec![result.row_id.to_string()];
+        if with_id {
+            record.push(result.id.clone().unwrap_or_default());
+        }
+        record.push(result.text.clone());
+
         // Add scores in the same order as labels
-        for label in &labels {
+        for label in labels {
             record.push(
                 result.scores.get(label)
                     .unwrap_or(&0.0)
                     .to_string()
             );
         }
-        
+
+        record.push(result.predicted_label.clone());
+        if with_confidence {
+            record.push(result.confidence.unwrap_or(0.0).to_string());
+        }
+
         writer.write_record(&record)?;
     }
 
-    writer.flush()?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Per-label sum/min/max over the rows seen so far, mergeable across
+/// chunks the same way sharded frequency tools fold per-shard partials
+/// into a single total.
+#[derive(Debug, Clone, Copy)]
+struct LabelStat {
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl LabelStat {
+    fn from_value(value: f64) -> LabelStat {
+        LabelStat { sum: value, min: value, max: value }
+    }
+
+    fn merge(self, other: LabelStat) -> LabelStat {
+        LabelStat {
+            sum: self.sum + other.sum,
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ChunkSummary {
+    total_rows: usize,
+    label_stats: HashMap<String, LabelStat>,
+}
+
+impl ChunkSummary {
+    fn from_results(results: &[RowResult]) -> ChunkSummary {
+        let mut label_stats: HashMap<String, LabelStat> = HashMap::new();
+        for result in results {
+            for (label, &score) in &result.scores {
+                label_stats.entry(label.clone())
+                    .and_modify(|stat| *stat = stat.merge(LabelStat::from_value(score)))
+                    .or_insert_with(|| LabelStat::from_value(score));
+            }
+        }
+        ChunkSummary { total_rows: results.len(), label_stats }
+    }
+
+    fn merge(mut self, other: ChunkSummary) -> ChunkSummary {
+        self.total_rows += other.total_rows;
+        for (label, stat) in other.label_stats {
+            self.label_stats.entry(label)
+                .and_modify(|existing| *existing = existing.merge(stat))
+                .or_insert(stat);
+        }
+        self
+    }
+
+    fn print(&self, labels: &[String]) {
+        println!("Processed {} rows", self.total_rows);
+        for label in labels {
+            if let Some(stat) = self.label_stats.get(label) {
+                println!(
+                    "  {label}: sum={:.4} min={:.4} max={:.4}",
+                    stat.sum, stat.min, stat.max
+                );
+            }
+        }
+    }
+}